@@ -1,85 +1,89 @@
+mod grammar_registry;
+mod stats;
+
 use std::{collections::HashSet, path::Path};
 
+use once_cell::sync::Lazy;
 use tree_sitter::{Parser, TreeCursor};
 
 use crate::style::HighlightConfiguration;
+pub use grammar_registry::{CodeLensConfig, GrammarConfig, GrammarRegistry};
+pub use stats::LineStats;
 
-const DEFAULT_CODE_LENS_LIST: &[&str] = &["source_file"];
-const DEFAULT_CODE_LENS_IGNORE_LIST: &[&str] = &["source_file"];
-const RUST_CODE_LENS_LIST: &[&str] =
-    &["source_file", "impl_item", "trait_item", "declaration_list"];
-const RUST_CODE_LENS_IGNORE_LIST: &[&str] =
-    &["source_file", "use_declaration", "line_comment"];
-const GO_CODE_LENS_LIST: &[&str] = &[
-    "source_file",
-    "type_declaration",
-    "type_spec",
-    "interface_type",
-    "method_spec_list",
-];
-const GO_CODE_LENS_IGNORE_LIST: &[&str] =
-    &["source_file", "comment", "line_comment"];
-
-#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
-pub enum LapceLanguage {
-    Rust,
-    Go,
-    Javascript,
-    Jsx,
-    Typescript,
-    Tsx,
-    Python,
-}
+const GRAMMARS_DIR: &str = "grammars";
+
+/// Settings file `code_lens.*` overrides are read from. Like `GRAMMARS_DIR`,
+/// it is resolved relative to the process's current working directory.
+const SETTINGS_PATH: &str = "settings.toml";
+
+static GRAMMAR_REGISTRY: Lazy<GrammarRegistry> = Lazy::new(|| {
+    let registry = GrammarRegistry::discover(Path::new(GRAMMARS_DIR));
+    grammar_registry::load_code_lens_settings(&registry, Path::new(SETTINGS_PATH));
+    registry
+});
+
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+pub struct LapceLanguage(String);
 
 impl LapceLanguage {
     pub fn from_path(path: &Path) -> Option<LapceLanguage> {
-        let extension = path.extension()?.to_str()?;
-        Some(match extension {
-            "rs" => LapceLanguage::Rust,
-            "js" => LapceLanguage::Javascript,
-            "jsx" => LapceLanguage::Jsx,
-            "ts" => LapceLanguage::Typescript,
-            "tsx" => LapceLanguage::Tsx,
-            "go" => LapceLanguage::Go,
-            "py" => LapceLanguage::Python,
-            _ => return None,
-        })
-    }
-
-    fn tree_sitter_language(&self) -> tree_sitter::Language {
-        match self {
-            LapceLanguage::Rust => tree_sitter_rust::language(),
-            LapceLanguage::Go => tree_sitter_go::language(),
-            LapceLanguage::Javascript => tree_sitter_javascript::language(),
-            LapceLanguage::Jsx => tree_sitter_javascript::language(),
-            LapceLanguage::Typescript => {
-                tree_sitter_typescript::language_typescript()
+        Self::from_path_and_content(path, None)
+    }
+
+    // Tries, in order: exact file name, extension, then a shebang's interpreter.
+    pub fn from_path_and_content(
+        path: &Path,
+        first_line: Option<&str>,
+    ) -> Option<LapceLanguage> {
+        if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+            if let Some(id) = GRAMMAR_REGISTRY.id_for_file_name(file_name) {
+                return Some(LapceLanguage(id.to_string()));
+            }
+        }
+
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            if let Some(id) = GRAMMAR_REGISTRY.id_for_extension(extension) {
+                return Some(LapceLanguage(id.to_string()));
             }
-            LapceLanguage::Tsx => tree_sitter_typescript::language_tsx(),
-            LapceLanguage::Python => tree_sitter_python::language(),
         }
+
+        let interpreter = first_line.and_then(shebang_interpreter)?;
+        let id = GRAMMAR_REGISTRY.id_for_shebang(interpreter)?;
+        Some(LapceLanguage(id.to_string()))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+
+    fn tree_sitter_language(&self) -> Option<tree_sitter::Language> {
+        GRAMMAR_REGISTRY.tree_sitter_language(&self.0)
     }
 
-    pub(crate) fn new_parser(&self) -> Parser {
-        let language = self.tree_sitter_language();
+    pub(crate) fn new_parser(&self) -> Option<Parser> {
+        let language = self.tree_sitter_language()?;
         let mut parser = Parser::new();
-        parser.set_language(language).unwrap();
-        parser
+        parser.set_language(language).ok()?;
+        Some(parser)
     }
 
-    pub(crate) fn new_highlight_config(&self) -> HighlightConfiguration {
-        let language = self.tree_sitter_language();
-        let query = match self {
-            LapceLanguage::Rust => tree_sitter_rust::HIGHLIGHT_QUERY,
-            LapceLanguage::Go => tree_sitter_go::HIGHLIGHT_QUERY,
-            LapceLanguage::Javascript => tree_sitter_javascript::HIGHLIGHT_QUERY,
-            LapceLanguage::Jsx => tree_sitter_javascript::JSX_HIGHLIGHT_QUERY,
-            LapceLanguage::Typescript => tree_sitter_typescript::HIGHLIGHT_QUERY,
-            LapceLanguage::Tsx => tree_sitter_typescript::HIGHLIGHT_QUERY,
-            LapceLanguage::Python => tree_sitter_python::HIGHLIGHT_QUERY,
-        };
+    pub(crate) fn new_highlight_config(&self) -> Option<&HighlightConfiguration> {
+        GRAMMAR_REGISTRY.highlight_config(&self.0)
+    }
 
-        HighlightConfiguration::new(language, query, "", "").unwrap()
+    /// Resolves the language named by an `@injection.language` capture (or
+    /// an `injection.language` property) to its `HighlightConfiguration`,
+    /// for splicing nested highlighting into this language's highlight
+    /// event stream, e.g. JS inside an HTML `<script>` block. `tree-sitter
+    /// -highlight` calls this once per injection match and handles the
+    /// `set_included_ranges`/event-splicing itself, bailing out of
+    /// recursive injections past its own depth limit; we only need to
+    /// supply the lookup, returning `None` when the named language has no
+    /// grammar loaded so that region keeps the parent's highlighting.
+    pub(crate) fn injection_callback(
+        name: &str,
+    ) -> Option<&'static HighlightConfiguration> {
+        GRAMMAR_REGISTRY.highlight_config(name)
     }
 
     pub(crate) fn walk_tree(
@@ -87,32 +91,44 @@ impl LapceLanguage {
         cursor: &mut TreeCursor,
         normal_lines: &mut HashSet<usize>,
     ) {
-        let (list, ignore_list) = match self {
-            LapceLanguage::Rust => (RUST_CODE_LENS_LIST, RUST_CODE_LENS_IGNORE_LIST),
-            LapceLanguage::Go => (GO_CODE_LENS_LIST, GO_CODE_LENS_IGNORE_LIST),
-            _ => (DEFAULT_CODE_LENS_LIST, DEFAULT_CODE_LENS_IGNORE_LIST),
-        };
-        walk_tree(cursor, 0, normal_lines, list, ignore_list);
+        let (list, ignore_list) = GRAMMAR_REGISTRY.code_lens(&self.0);
+        walk_tree(cursor, 0, normal_lines, &list, &ignore_list);
     }
 }
 
+/// Pulls the interpreter basename out of a shebang line, e.g.
+/// `"#!/usr/bin/env python3"` or `"#!/bin/sh"` both yield `Some("python3")`
+/// / `Some("sh")`. Returns `None` if `line` isn't a shebang.
+fn shebang_interpreter(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("#!")?.trim();
+    let mut parts = rest.split_whitespace();
+    let mut program = parts.next()?;
+    // `#!/usr/bin/env python3` names the real interpreter as an argument
+    // to `env` rather than as the shebang program itself. Flags given to
+    // `env` (e.g. `-S`) are skipped.
+    if Path::new(program).file_name().and_then(|n| n.to_str()) == Some("env") {
+        program = parts.find(|part| !part.starts_with('-'))?;
+    }
+    Path::new(program).file_name().and_then(|n| n.to_str())
+}
+
 fn walk_tree(
     cursor: &mut TreeCursor,
     level: usize,
     normal_lines: &mut HashSet<usize>,
-    list: &[&str],
-    ignore_list: &[&str],
+    list: &[String],
+    ignore_list: &[String],
 ) {
     let node = cursor.node();
     let start_pos = node.start_position();
     let end_pos = node.end_position();
     let kind = node.kind().trim();
-    if !ignore_list.contains(&kind) && !kind.is_empty() {
+    if !ignore_list.iter().any(|k| k == kind) && !kind.is_empty() {
         normal_lines.insert(start_pos.row);
         normal_lines.insert(end_pos.row);
     }
 
-    if list.contains(&kind) && cursor.goto_first_child() {
+    if list.iter().any(|k| k == kind) && cursor.goto_first_child() {
         loop {
             walk_tree(cursor, level + 1, normal_lines, list, ignore_list);
             if !cursor.goto_next_sibling() {
@@ -122,3 +138,41 @@ fn walk_tree(
         cursor.goto_parent();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::shebang_interpreter;
+
+    #[test]
+    fn plain_shebang() {
+        assert_eq!(shebang_interpreter("#!/bin/bash"), Some("bash"));
+    }
+
+    #[test]
+    fn env_shebang_names_the_wrapped_interpreter() {
+        assert_eq!(
+            shebang_interpreter("#!/usr/bin/env python3"),
+            Some("python3")
+        );
+    }
+
+    #[test]
+    fn env_flags_are_skipped() {
+        // `env -S` forwards further arguments to the interpreter; the flag
+        // itself is skipped so the interpreter name is still found.
+        assert_eq!(
+            shebang_interpreter("#!/usr/bin/env -S python3 -u"),
+            Some("python3")
+        );
+    }
+
+    #[test]
+    fn not_a_shebang() {
+        assert_eq!(shebang_interpreter("fn main() {}"), None);
+    }
+
+    #[test]
+    fn empty_shebang() {
+        assert_eq!(shebang_interpreter("#!"), None);
+    }
+}
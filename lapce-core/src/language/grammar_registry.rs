@@ -0,0 +1,332 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{anyhow, Result};
+use libloading::{Library, Symbol};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::style::HighlightConfiguration;
+
+// Scope names Lapce's themes assign colors to; matched against each
+// query's capture names by `HighlightConfiguration::configure`.
+const SCOPES: &[&str] = &[
+    "attribute",
+    "constant",
+    "constant.builtin",
+    "constructor",
+    "comment",
+    "escape",
+    "function",
+    "function.builtin",
+    "function.macro",
+    "function.method",
+    "keyword",
+    "label",
+    "operator",
+    "property",
+    "punctuation.bracket",
+    "punctuation.delimiter",
+    "string",
+    "tag",
+    "type",
+    "type.builtin",
+    "variable",
+    "variable.builtin",
+    "variable.parameter",
+];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrammarConfig {
+    pub id: String,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    // Exact file names handled by this grammar regardless of extension,
+    // e.g. `"Makefile"`, `"Dockerfile"`.
+    #[serde(default)]
+    pub file_names: Vec<String>,
+    // Interpreter basenames (the last path component of a shebang's
+    // interpreter, e.g. `"python3"`) that should resolve to this grammar
+    // when a file has no recognized extension or name.
+    #[serde(default)]
+    pub shebangs: Vec<String>,
+    // Node kinds the code-lens traversal walks into (`list`) and skips
+    // when deciding whether a node anchors a lens (`ignore`). Left empty
+    // to fall back to this id's built-in defaults, see `built_in_code_lens`.
+    #[serde(default)]
+    pub code_lens: CodeLensConfig,
+    // Node kinds that mark a comment, e.g. `["line_comment",
+    // "block_comment"]` for Rust. Left empty to fall back to
+    // `built_in_comment_kinds`.
+    #[serde(default)]
+    pub comment_kinds: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CodeLensConfig {
+    #[serde(default)]
+    pub list: Vec<String>,
+    #[serde(default)]
+    pub ignore: Vec<String>,
+}
+
+impl CodeLensConfig {
+    fn is_empty(&self) -> bool {
+        self.list.is_empty() && self.ignore.is_empty()
+    }
+}
+
+struct Grammar {
+    config: GrammarConfig,
+    queries_dir: PathBuf,
+    language: tree_sitter::Language,
+    highlight_config: OnceCell<HighlightConfiguration>,
+    // Declared last: fields drop top to bottom, and `language` and
+    // `highlight_config` both point into this library's mapped memory.
+    _library: Library,
+}
+
+#[derive(Default)]
+pub struct GrammarRegistry {
+    grammars: Vec<Grammar>,
+    by_id: HashMap<String, usize>,
+    by_extension: HashMap<String, usize>,
+    by_file_name: HashMap<String, usize>,
+    by_shebang: HashMap<String, usize>,
+    code_lens_overrides: RwLock<HashMap<String, CodeLensConfig>>,
+}
+
+impl GrammarRegistry {
+    pub fn discover(dir: &Path) -> GrammarRegistry {
+        let mut registry = GrammarRegistry::default();
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("no grammars directory at {}: {err}", dir.display());
+                return registry;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            match load_one(&path) {
+                Ok(grammar) => registry.insert(grammar),
+                Err(err) => {
+                    log::warn!("failed to load grammar at {}: {err}", path.display());
+                }
+            }
+        }
+
+        registry
+    }
+
+    fn insert(&mut self, grammar: Grammar) {
+        let index = self.grammars.len();
+        self.by_id.insert(grammar.config.id.clone(), index);
+        for extension in &grammar.config.extensions {
+            self.by_extension.insert(extension.clone(), index);
+        }
+        for file_name in &grammar.config.file_names {
+            self.by_file_name.insert(file_name.clone(), index);
+        }
+        for shebang in &grammar.config.shebangs {
+            self.by_shebang.insert(shebang.clone(), index);
+        }
+        self.grammars.push(grammar);
+    }
+
+    pub fn id_for_extension(&self, extension: &str) -> Option<&str> {
+        self.by_extension
+            .get(extension)
+            .map(|&i| self.grammars[i].config.id.as_str())
+    }
+
+    // Looked up by exact file name, e.g. `"Makefile"`.
+    pub fn id_for_file_name(&self, file_name: &str) -> Option<&str> {
+        self.by_file_name
+            .get(file_name)
+            .map(|&i| self.grammars[i].config.id.as_str())
+    }
+
+    // Looked up by shebang interpreter basename, e.g. `"python3"`.
+    pub fn id_for_shebang(&self, interpreter: &str) -> Option<&str> {
+        self.by_shebang
+            .get(interpreter)
+            .map(|&i| self.grammars[i].config.id.as_str())
+    }
+
+    pub fn config(&self, id: &str) -> Option<&GrammarConfig> {
+        self.by_id.get(id).map(|&i| &self.grammars[i].config)
+    }
+
+    pub fn tree_sitter_language(&self, id: &str) -> Option<tree_sitter::Language> {
+        self.by_id.get(id).map(|&i| self.grammars[i].language.clone())
+    }
+
+    // Lets user settings override the code-lens node-kind lists for a
+    // language, taking priority over both the grammar's own `config.toml`
+    // and the built-in defaults.
+    pub fn set_code_lens_override(&self, id: &str, config: CodeLensConfig) {
+        self.code_lens_overrides
+            .write()
+            .unwrap()
+            .insert(id.to_string(), config);
+    }
+
+    // Resolves the code-lens node-kind list and ignore-list for `id`, in
+    // priority order: a user override (see `load_code_lens_settings`), then
+    // the grammar's own `config.toml`, then `built_in_code_lens`.
+    pub fn code_lens(&self, id: &str) -> (Vec<String>, Vec<String>) {
+        if let Some(config) = self.code_lens_overrides.read().unwrap().get(id) {
+            if !config.is_empty() {
+                return (config.list.clone(), config.ignore.clone());
+            }
+        }
+        if let Some(config) = self.config(id) {
+            if !config.code_lens.is_empty() {
+                return (config.code_lens.list.clone(), config.code_lens.ignore.clone());
+            }
+        }
+        built_in_code_lens(id)
+    }
+
+    // Resolves the comment node-kind list for `id`: the grammar's own
+    // `config.toml` if it set one, else `built_in_comment_kinds`.
+    pub fn comment_kinds(&self, id: &str) -> Vec<String> {
+        match self.config(id) {
+            Some(config) if !config.comment_kinds.is_empty() => {
+                config.comment_kinds.clone()
+            }
+            _ => built_in_comment_kinds(id),
+        }
+    }
+
+    pub fn highlight_config(&self, id: &str) -> Option<&HighlightConfiguration> {
+        let grammar = self.by_id.get(id).map(|&i| &self.grammars[i])?;
+        grammar
+            .highlight_config
+            .get_or_try_init(|| build_highlight_config(grammar))
+            .ok()
+    }
+}
+
+fn built_in_code_lens(id: &str) -> (Vec<String>, Vec<String>) {
+    let (list, ignore): (&[&str], &[&str]) = match id {
+        "rust" => (
+            &["source_file", "impl_item", "trait_item", "declaration_list"],
+            &["source_file", "use_declaration", "line_comment"],
+        ),
+        "go" => (
+            &[
+                "source_file",
+                "type_declaration",
+                "type_spec",
+                "interface_type",
+                "method_spec_list",
+            ],
+            &["source_file", "comment", "line_comment"],
+        ),
+        "javascript" | "jsx" | "typescript" | "tsx" => (
+            &["source_file", "function_declaration", "class_declaration"],
+            &["source_file", "comment"],
+        ),
+        "python" => (
+            &["module", "function_definition", "class_definition"],
+            &["module", "comment"],
+        ),
+        _ => (&["source_file"], &["source_file"]),
+    };
+    (to_owned(list), to_owned(ignore))
+}
+
+fn to_owned(kinds: &[&str]) -> Vec<String> {
+    kinds.iter().map(|kind| kind.to_string()).collect()
+}
+
+fn built_in_comment_kinds(id: &str) -> Vec<String> {
+    let kinds: &[&str] = match id {
+        "rust" => &["line_comment", "block_comment"],
+        _ => &["comment"],
+    };
+    to_owned(kinds)
+}
+
+#[derive(Default, Deserialize)]
+struct CodeLensSettings {
+    #[serde(default)]
+    code_lens: HashMap<String, CodeLensConfig>,
+}
+
+/// Reads `[code_lens.<id>]` tables out of `path` (Lapce's settings file)
+/// and installs each as an override on `registry`. A missing or
+/// unparsable file just means no overrides are configured.
+pub fn load_code_lens_settings(registry: &GrammarRegistry, path: &Path) {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return;
+    };
+    let Ok(settings) = toml::from_str::<CodeLensSettings>(&contents) else {
+        return;
+    };
+    for (id, config) in settings.code_lens {
+        registry.set_code_lens_override(&id, config);
+    }
+}
+
+fn build_highlight_config(grammar: &Grammar) -> Result<HighlightConfiguration> {
+    let highlights = read_query(&grammar.queries_dir, "highlights.scm");
+    let injections = read_query(&grammar.queries_dir, "injections.scm");
+    let locals = read_query(&grammar.queries_dir, "locals.scm");
+
+    let mut config =
+        HighlightConfiguration::new(grammar.language.clone(), &highlights, &injections, &locals)
+            .map_err(|err| anyhow!("{}: invalid query: {err:?}", grammar.config.id))?;
+    config.configure(SCOPES);
+    Ok(config)
+}
+
+fn read_query(queries_dir: &Path, file_name: &str) -> String {
+    fs::read_to_string(queries_dir.join(file_name)).unwrap_or_default()
+}
+
+fn load_one(dir: &Path) -> Result<Grammar> {
+    let config: GrammarConfig =
+        toml::from_str(&fs::read_to_string(dir.join("config.toml"))?)?;
+
+    let library_path = find_library(dir)
+        .ok_or_else(|| anyhow!("no dynamic library in {}", dir.display()))?;
+    // Safety: we trust libraries placed in the user's grammars directory
+    // to export a well-formed `tree_sitter_<id>` constructor, the same
+    // contract tree-sitter CLI-generated grammars follow.
+    let library = unsafe { Library::new(&library_path)? };
+    let symbol_name = format!("tree_sitter_{}\0", config.id);
+    let language = unsafe {
+        let constructor: Symbol<unsafe extern "C" fn() -> tree_sitter::Language> =
+            library.get(symbol_name.as_bytes())?;
+        constructor()
+    };
+
+    Ok(Grammar {
+        queries_dir: dir.join("queries"),
+        config,
+        _library: library,
+        language,
+        highlight_config: OnceCell::new(),
+    })
+}
+
+fn find_library(dir: &Path) -> Option<PathBuf> {
+    let dll_extension = std::env::consts::DLL_EXTENSION;
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.extension().and_then(|ext| ext.to_str()) == Some(dll_extension))
+}
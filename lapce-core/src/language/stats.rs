@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+
+use tree_sitter::TreeCursor;
+
+use super::{LapceLanguage, GRAMMAR_REGISTRY};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineStats {
+    pub code: usize,
+    pub comments: usize,
+    pub blanks: usize,
+}
+
+impl LapceLanguage {
+    pub fn line_stats(&self, text: &str) -> LineStats {
+        let total_lines = text.lines().count();
+
+        let tree = self
+            .new_parser()
+            .and_then(|mut parser| parser.parse(text, None));
+        let Some(tree) = tree else {
+            let blanks = text.lines().filter(|line| line.trim().is_empty()).count();
+            return LineStats { code: total_lines - blanks, comments: 0, blanks };
+        };
+
+        let comment_kinds = GRAMMAR_REGISTRY.comment_kinds(self.id());
+        let mut code_rows = HashSet::new();
+        let mut comment_rows = HashSet::new();
+        walk_tree(&mut tree.walk(), &comment_kinds, &mut code_rows, &mut comment_rows);
+
+        let mut stats = LineStats::default();
+        for row in 0..total_lines {
+            if code_rows.contains(&row) {
+                stats.code += 1;
+            } else if comment_rows.contains(&row) {
+                stats.comments += 1;
+            } else {
+                stats.blanks += 1;
+            }
+        }
+        stats
+    }
+}
+
+fn walk_tree(
+    cursor: &mut TreeCursor,
+    comment_kinds: &[String],
+    code_rows: &mut HashSet<usize>,
+    comment_rows: &mut HashSet<usize>,
+) {
+    let node = cursor.node();
+    if node.child_count() == 0 && !node.kind().is_empty() {
+        let rows = node.start_position().row..=node.end_position().row;
+        if comment_kinds.iter().any(|k| k == node.kind()) {
+            comment_rows.extend(rows);
+        } else {
+            code_rows.extend(rows);
+        }
+    }
+
+    if cursor.goto_first_child() {
+        loop {
+            walk_tree(cursor, comment_kinds, code_rows, comment_rows);
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+        cursor.goto_parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineStats;
+    use crate::language::LapceLanguage;
+
+    #[test]
+    fn default_stats_are_zero() {
+        assert_eq!(
+            LineStats::default(),
+            LineStats { code: 0, comments: 0, blanks: 0 }
+        );
+    }
+
+    #[test]
+    fn fallback_blank_line_counting_when_no_parser_is_available() {
+        // No grammar is registered for this id in tests, so `line_stats`
+        // takes the no-parser fallback branch.
+        let lang = LapceLanguage("nonexistent".to_string());
+        let stats = lang.line_stats("a\n\nb\n\n\n");
+        assert_eq!(stats, LineStats { code: 2, comments: 0, blanks: 3 });
+    }
+}